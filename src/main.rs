@@ -1,5 +1,7 @@
 use clap::Parser;
-use image::{DynamicImage, GenericImageView};
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, DynamicImage, GenericImageView, Rgba};
+use serde::Deserialize;
 use std::fmt;
 use std::io::{self, stdin};
 use std::{thread, time};
@@ -14,7 +16,6 @@ struct Args {
     #[arg(
         short,
         long,
-        default_value = DEFAULT_CHARS,
         help = "provide a list of chars from least to most intense, separated by whitespace"
     )]
     chars: Option<String>,
@@ -23,64 +24,163 @@ struct Args {
         short,
         long,
         help = "scale factor as a positive integer",
-        default_value = "3"
+        value_parser = clap::value_parser!(u32).range(1..)
     )]
     scale: Option<u32>,
+
+    #[arg(
+        long,
+        help = "color each glyph with its source pixel using 24-bit ANSI escapes"
+    )]
+    color: bool,
+
+    #[arg(short, long, help = "write the rendered output to a file instead of stdout")]
+    output: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Text,
+        help = "output format for the rendered art"
+    )]
+    format: Format,
+
+    #[arg(long, help = "loop animated GIFs until interrupted")]
+    r#loop: bool,
+
+    #[arg(long, help = "cap animation playback to at most this many frames per second")]
+    fps_cap: Option<u32>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Filter::Triangle,
+        help = "resampling filter used to downscale the image"
+    )]
+    filter: Filter,
+
+    #[arg(
+        long,
+        help = "average luminance over each source cell block instead of point sampling"
+    )]
+    average: bool,
     src: Option<String>,
 }
 
+#[derive(Copy, Clone, Debug, clap::ValueEnum)]
+enum Filter {
+    Nearest,
+    Triangle,
+    Lanczos3,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, clap::ValueEnum)]
+enum Format {
+    Text,
+    Ansi,
+    Html,
+}
+impl From<Filter> for image::imageops::FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Nearest => image::imageops::FilterType::Nearest,
+            Filter::Triangle => image::imageops::FilterType::Triangle,
+            Filter::Lanczos3 => image::imageops::FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Config {
+    chars: Option<String>,
+    scale: Option<u32>,
+    color: Option<bool>,
+}
+impl Config {
+    fn load() -> Result<Self, AppError> {
+        let path = match dirs::config_dir() {
+            Some(dir) => dir.join("ascii-art").join("config.json"),
+            None => return Ok(Self::default()),
+        };
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&contents).map_err(|e| AppError::Config(e.to_string()))
+    }
+}
+
 #[derive(Debug)]
-struct AppError {
-    kind: String,
-    detail: String,
+enum AppError {
+    Io(io::Error),
+    Image(image::ImageError),
+    Config(String),
+    StdinEmpty,
+    NoImage,
 }
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let err_msg = match self.kind.as_str() {
-            "io" => format!("io error: {}", self.detail),
-            "img" => format!("load img error: {}", self.detail),
-            "conf" => format!("configuration error: {}", self.detail),
-            _ => format!("Unknown error occured: {}", self.detail),
-        };
-        write!(f, "{}", err_msg)
+        match self {
+            AppError::Io(e) => write!(f, "io error: {}", e),
+            AppError::Image(e) => write!(f, "load img error: {}", e),
+            AppError::Config(detail) => write!(f, "configuration error: {}", detail),
+            AppError::StdinEmpty => write!(f, "io error: stdin is empty"),
+            AppError::NoImage => write!(f, "load img error: no image selected"),
+        }
     }
 }
 impl From<io::Error> for AppError {
     fn from(error: io::Error) -> Self {
-        AppError {
-            kind: String::from("io"),
-            detail: error.to_string(),
-        }
+        AppError::Io(error)
+    }
+}
+impl From<image::ImageError> for AppError {
+    fn from(error: image::ImageError) -> Self {
+        AppError::Image(error)
     }
 }
 
 #[derive(Debug)]
 struct AsciiPrinter {
-    src_img: Option<Result<DynamicImage, AppError>>,
+    src_path: Option<String>,
     chars: Vec<char>,
     scale: u32,
+    format: Format,
+    output: Option<String>,
+    loop_anim: bool,
+    fps_cap: Option<u32>,
+    filter: image::imageops::FilterType,
+    average: bool,
 }
 impl Default for AsciiPrinter {
     fn default() -> Self {
         let mut chars: Vec<char> = vec![' '];
         chars.extend(DEFAULT_CHARS.replace(' ', "").chars());
         Self {
-            src_img: None,
+            src_path: None,
             chars,
             scale: 3,
+            format: Format::Text,
+            output: None,
+            loop_anim: false,
+            fps_cap: None,
+            filter: image::imageops::FilterType::Triangle,
+            average: false,
         }
     }
 }
 impl AsciiPrinter {
     fn load_image(self, src: &str) -> Self {
-        let src_img = Some(image::open(src).map_err(|e| AppError {
-            kind: "img".to_string(),
-            detail: e.to_string(),
-        }));
         AsciiPrinter {
-            src_img,
+            src_path: Some(src.to_string()),
             chars: self.chars,
             scale: self.scale,
+            format: self.format,
+            output: self.output,
+            loop_anim: self.loop_anim,
+            fps_cap: self.fps_cap,
+            filter: self.filter,
+            average: self.average,
         }
     }
     fn set_chars(self, intense_chars: String) -> Self {
@@ -88,16 +188,68 @@ impl AsciiPrinter {
         chars.push(' ');
         chars.extend(intense_chars.chars());
         AsciiPrinter {
-            src_img: self.src_img,
+            src_path: self.src_path,
             chars,
             scale: self.scale,
+            format: self.format,
+            output: self.output,
+            loop_anim: self.loop_anim,
+            fps_cap: self.fps_cap,
+            filter: self.filter,
+            average: self.average,
         }
     }
     fn set_scale(self, scale: u32) -> Self {
         AsciiPrinter {
-            src_img: self.src_img,
+            src_path: self.src_path,
+            chars: self.chars,
+            // Guard against a zero scale from the config file (CLI already rejects it).
+            scale: scale.max(1),
+            format: self.format,
+            output: self.output,
+            loop_anim: self.loop_anim,
+            fps_cap: self.fps_cap,
+            filter: self.filter,
+            average: self.average,
+        }
+    }
+    fn set_output(self, format: Format, output: Option<String>) -> Self {
+        AsciiPrinter {
+            src_path: self.src_path,
+            chars: self.chars,
+            scale: self.scale,
+            format,
+            output,
+            loop_anim: self.loop_anim,
+            fps_cap: self.fps_cap,
+            filter: self.filter,
+            average: self.average,
+        }
+    }
+    fn set_animation(self, loop_anim: bool, fps_cap: Option<u32>) -> Self {
+        AsciiPrinter {
+            src_path: self.src_path,
             chars: self.chars,
-            scale,
+            scale: self.scale,
+            format: self.format,
+            output: self.output,
+            loop_anim,
+            fps_cap,
+            filter: self.filter,
+            average: self.average,
+        }
+    }
+    fn set_resampling(self, filter: image::imageops::FilterType, average: bool) -> Self {
+        AsciiPrinter {
+            src_path: self.src_path,
+            chars: self.chars,
+            scale: self.scale,
+            format: self.format,
+            output: self.output,
+            loop_anim: self.loop_anim,
+            fps_cap: self.fps_cap,
+            filter,
+            average,
         }
     }
     fn get_char(&self, intensity: f32) -> char {
@@ -105,42 +257,160 @@ impl AsciiPrinter {
         self.chars[index as usize]
     }
 
-    fn get_pixel_intensity(red: u8, green: u8, blue: u8) -> f32 {
-        0.2989 * red as f32 + 0.5870 * green as f32 + 0.1140 * blue as f32
+    fn get_pixel_intensity(pix: Rgba<u8>) -> f32 {
+        0.2989 * pix[0] as f32 + 0.5870 * pix[1] as f32 + 0.1140 * pix[2] as f32
     }
 
-    fn into_print(self) -> Result<(), AppError> {
-        if self.src_img.is_none() {
-            return Err(AppError {
-                kind: "img".to_string(),
-                detail: "no image selected".to_string(),
-            });
-        };
-        let src = self.src_img.as_ref().unwrap();
-        if src.is_err() {
-            return Err(AppError {
-                kind: "img".to_string(),
-                detail: src.as_ref().unwrap_err().to_string(),
-            });
-        }
-        let src = src.as_ref().unwrap();
+    fn cell(&self, pix: Rgba<u8>) -> String {
+        let intensity = Self::get_pixel_intensity(pix);
+        let ch = self.get_char(intensity);
+        match self.format {
+            Format::Text => ch.to_string(),
+            Format::Ansi => format!("\x1b[38;2;{};{};{}m{}\x1b[0m", pix[0], pix[1], pix[2], ch),
+            Format::Html => format!(
+                "<span style=\"color:#{:02x}{:02x}{:02x}\">{}</span>",
+                pix[0],
+                pix[1],
+                pix[2],
+                html_escape(ch)
+            ),
+        }
+    }
+
+    fn render(&self, src: &DynamicImage) -> Vec<String> {
         let (width, height) = src.dimensions();
-        for y in 0..height {
-            for x in 0..width {
-                if y % (self.scale * 2) == 0 && x % self.scale == 0 {
-                    let pix = src.get_pixel(x, y);
-                    let intensity = Self::get_pixel_intensity(pix[0], pix[1], pix[2]);
-                    let char = self.get_char(intensity);
-                    print!("{}", char);
+        // Keep the 2:1 vertical compensation for the character cell aspect ratio.
+        let cols = (width / self.scale).max(1);
+        let rows = (height / (self.scale * 2)).max(1);
+        let buf = src.to_rgba8();
+        let mut lines = Vec::with_capacity(rows as usize);
+        if self.average {
+            for cy in 0..rows {
+                let mut line = String::new();
+                for cx in 0..cols {
+                    let x0 = cx * self.scale;
+                    let y0 = cy * self.scale * 2;
+                    let (mut rs, mut gs, mut bs, mut n) = (0u64, 0u64, 0u64, 0u64);
+                    for y in y0..(y0 + self.scale * 2).min(height) {
+                        for x in x0..(x0 + self.scale).min(width) {
+                            let pix = buf.get_pixel(x, y);
+                            if pix[3] == 0 {
+                                continue;
+                            }
+                            rs += pix[0] as u64;
+                            gs += pix[1] as u64;
+                            bs += pix[2] as u64;
+                            n += 1;
+                        }
+                    }
+                    if n == 0 {
+                        line.push(' ');
+                        continue;
+                    }
+                    line.push_str(&self.cell(Rgba([
+                        (rs / n) as u8,
+                        (gs / n) as u8,
+                        (bs / n) as u8,
+                        255,
+                    ])));
+                }
+                lines.push(line);
+            }
+        } else {
+            let resized = image::imageops::resize(&buf, cols, rows, self.filter);
+            for y in 0..rows {
+                let mut line = String::new();
+                for x in 0..cols {
+                    let pix = resized.get_pixel(x, y);
+                    if pix[3] == 0 {
+                        line.push(' ');
+                        continue;
+                    }
+                    line.push_str(&self.cell(*pix));
                 }
+                lines.push(line);
+            }
+        }
+        lines
+    }
+
+    fn assemble(&self, lines: &[String]) -> String {
+        match self.format {
+            Format::Html => format!("<pre>\n{}\n</pre>", lines.join("\n")),
+            _ => lines.join("\n"),
+        }
+    }
+
+    fn emit(&self, src: &DynamicImage) -> Result<(), AppError> {
+        let body = self.assemble(&self.render(src));
+        match &self.output {
+            Some(path) => std::fs::write(path, body)?,
+            None => println!("{}", body),
+        }
+        Ok(())
+    }
+
+    fn decode_frames(path: &str) -> Result<Option<Vec<(DynamicImage, time::Duration)>>, AppError> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let decoder = match GifDecoder::new(file) {
+            Ok(decoder) => decoder,
+            // Not an animated container; the caller falls back to a single decode.
+            Err(_) => return Ok(None),
+        };
+        let mut frames = Vec::new();
+        for frame in decoder.into_frames() {
+            let frame = frame?;
+            let delay: time::Duration = frame.delay().into();
+            frames.push((DynamicImage::ImageRgba8(frame.into_buffer()), delay));
+        }
+        Ok(Some(frames))
+    }
+
+    fn play(&self, frames: Vec<(DynamicImage, time::Duration)>) -> Result<(), AppError> {
+        // A file sink can't hold an animation, so export the first frame there.
+        if self.output.is_some() {
+            return self.emit(&frames[0].0);
+        }
+        loop {
+            for (img, delay) in &frames {
+                print!("\x1b[2J\x1b[H");
+                println!("{}", self.assemble(&self.render(img)));
+                let delay = match self.fps_cap {
+                    Some(cap) if cap > 0 => (*delay).max(time::Duration::from_secs(1) / cap),
+                    _ => *delay,
+                };
+                thread::sleep(delay);
             }
-            if y % (self.scale * 2) == 0 {
-                println!();
+            if !self.loop_anim {
+                break;
             }
         }
-        println!();
         Ok(())
     }
+
+    fn into_print(self) -> Result<(), AppError> {
+        let path = self.src_path.clone().ok_or(AppError::NoImage)?;
+        // Detect animations by the actual frame count rather than the file name.
+        if let Some(frames) = Self::decode_frames(&path)? {
+            if frames.len() > 1 {
+                return self.play(frames);
+            }
+            if let Some((img, _)) = frames.into_iter().next() {
+                return self.emit(&img);
+            }
+        }
+        let src = image::open(&path)?;
+        self.emit(&src)
+    }
+}
+
+fn html_escape(ch: char) -> String {
+    match ch {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        _ => ch.to_string(),
+    }
 }
 
 fn read_stdin() -> Result<String, AppError> {
@@ -154,34 +424,45 @@ fn read_stdin() -> Result<String, AppError> {
     });
     thread::sleep(time::Duration::from_millis(10));
     let line = rx.try_recv();
-    if let Err(e) = line {
+    if line.is_err() {
         println!("No image selected, run with --help for more info");
-        return Err(AppError {
-            kind: "io".to_string(),
-            detail: e.to_string(),
-        });
+        return Err(AppError::StdinEmpty);
     }
     let line = line.unwrap();
     if line.is_empty() {
-        Err(AppError {
-            kind: "io".to_string(),
-            detail: "stdin is empty".to_string(),
-        })
+        Err(AppError::StdinEmpty)
     } else {
         Ok(line)
     }
 }
 fn main() -> Result<(), AppError> {
     let args = Args::parse();
+    let config = Config::load()?;
     let image_path = match args.src.as_deref() {
         Some(src) => src.to_string(),
         None => read_stdin()?,
     };
     println!("selected img {}", image_path);
+    let chars = args
+        .chars
+        .or(config.chars)
+        .unwrap_or_else(|| DEFAULT_CHARS.replace(' ', ""));
+    let scale = args.scale.or(config.scale).unwrap_or(3);
+    let color = args.color || config.color.unwrap_or(false);
+    // `--color` (or `color` in the config file) turns text output into ANSI output;
+    // html output is kept as it is.
+    let format = if color && args.format == Format::Text {
+        Format::Ansi
+    } else {
+        args.format
+    };
     let printer = AsciiPrinter::default();
     printer
         .load_image(&image_path)
-        .set_chars(args.chars.unwrap())
-        .set_scale(args.scale.unwrap())
+        .set_chars(chars)
+        .set_scale(scale)
+        .set_output(format, args.output)
+        .set_animation(args.r#loop, args.fps_cap)
+        .set_resampling(args.filter.into(), args.average)
         .into_print()
 }